@@ -116,6 +116,18 @@ impl CpuId {
         }
     }
 
+    /// Creates a new `CpuId` containing exactly the given `entries`.
+    ///
+    /// # Arguments
+    ///
+    /// * `entries` - The CPUID entries to populate the new `CpuId` with.
+    ///
+    pub fn from_entries(entries: &[kvm_cpuid_entry2]) -> CpuId {
+        let mut cpuid = CpuId::new(entries.len());
+        cpuid.mut_entries_slice().copy_from_slice(entries);
+        cpuid
+    }
+
     /// Get the mutable entries slice so they can be modified before passing to the VCPU.
     ///
     pub fn mut_entries_slice(&mut self) -> &mut [kvm_cpuid_entry2] {
@@ -128,6 +140,54 @@ impl CpuId {
         unsafe { self.kvm_cpuid[0].entries.as_mut_slice(nent) }
     }
 
+    /// Get the entries slice.
+    ///
+    pub fn entries(&self) -> &[kvm_cpuid_entry2] {
+        let nent = (self.kvm_cpuid[0].nent as usize).min(self.allocated_len);
+        unsafe { self.kvm_cpuid[0].entries.as_slice(nent) }
+    }
+
+    /// Returns an iterator over the CPUID entries.
+    ///
+    pub fn iter(&self) -> std::slice::Iter<kvm_cpuid_entry2> {
+        self.entries().iter()
+    }
+
+    /// Appends `entry` to the end of the entry list.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `ENOSPC` error if the entry list is already holding `allocated_len` entries.
+    ///
+    pub fn push(&mut self, entry: kvm_cpuid_entry2) -> Result<()> {
+        let nent = self.kvm_cpuid[0].nent as usize;
+        if nent >= self.allocated_len {
+            return Err(io::Error::from_raw_os_error(libc::ENOSPC));
+        }
+
+        // Safe because `allocated_len` is the number of `kvm_cpuid_entry2` trailing entries the
+        // backing storage was allocated with, and `nent < allocated_len` was just checked above.
+        unsafe { self.kvm_cpuid[0].entries.as_mut_slice(self.allocated_len)[nent] = entry };
+        self.kvm_cpuid[0].nent = (nent + 1) as u32;
+        Ok(())
+    }
+
+    /// Retains only the entries for which `f` returns `true`, removing the rest and shrinking
+    /// the entry list in place.
+    ///
+    pub fn retain<F: FnMut(&kvm_cpuid_entry2) -> bool>(&mut self, mut f: F) {
+        let mut kept = 0;
+        let nent = self.kvm_cpuid[0].nent as usize;
+        let entries = self.mut_entries_slice();
+        for i in 0..nent {
+            if f(&entries[i]) {
+                entries[kept] = entries[i];
+                kept += 1;
+            }
+        }
+        self.kvm_cpuid[0].nent = kept as u32;
+    }
+
     /// Get a  pointer so it can be passed to the kernel. Using this pointer is unsafe.
     ///
     pub fn as_ptr(&self) -> *const kvm_cpuid2 {
@@ -141,6 +201,38 @@ impl CpuId {
     }
 }
 
+/// Builds a `kvm_cpuid_entry2` out of a raw host CPUID leaf, as returned by the `cpuid`
+/// instruction for the given `function`/`index` pair. This lets callers synthesize a CPUID
+/// entry (e.g. after masking or patching a topology leaf) without going through
+/// `KVM_GET_SUPPORTED_CPUID`.
+///
+/// # Arguments
+///
+/// * `function` - The CPUID leaf number (`eax` on input).
+/// * `index` - The CPUID sub-leaf number (`ecx` on input), used for leaves with the
+///   `KVM_CPUID_FLAG_SIGNIFCANT_INDEX` semantics.
+/// * `eax`, `ebx`, `ecx`, `edx` - The raw host CPUID result registers for that leaf.
+///
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub fn cpuid_entry2_from_raw(
+    function: u32,
+    index: u32,
+    eax: u32,
+    ebx: u32,
+    ecx: u32,
+    edx: u32,
+) -> kvm_cpuid_entry2 {
+    kvm_cpuid_entry2 {
+        function,
+        index,
+        eax,
+        ebx,
+        ecx,
+        edx,
+        ..Default::default()
+    }
+}
+
 /// A safe wrapper over the `kvm_run` struct.
 ///
 /// The wrapper is needed for sending the pointer to `kvm_run` between
@@ -196,3 +288,58 @@ impl KvmRunWrapper {
         }
     }
 }
+
+#[cfg(test)]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+
+    fn entry(function: u32) -> kvm_cpuid_entry2 {
+        kvm_cpuid_entry2 {
+            function,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_cpuid_from_entries() {
+        let entries = vec![entry(1), entry(2), entry(3)];
+        let cpuid = CpuId::from_entries(&entries);
+        assert_eq!(cpuid.entries(), entries.as_slice());
+    }
+
+    #[test]
+    fn test_cpuid_push() {
+        let mut cpuid = CpuId::new(2);
+        cpuid.retain(|_| false);
+        assert_eq!(cpuid.entries().len(), 0);
+
+        cpuid.push(entry(1)).unwrap();
+        cpuid.push(entry(2)).unwrap();
+        assert_eq!(cpuid.entries(), [entry(1), entry(2)].as_ref());
+
+        // The entry list is now full (back up to `allocated_len`), so a further push fails.
+        let err = cpuid.push(entry(3)).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOSPC));
+    }
+
+    #[test]
+    fn test_cpuid_retain() {
+        let entries = vec![entry(1), entry(2), entry(3)];
+        let mut cpuid = CpuId::from_entries(&entries);
+
+        cpuid.retain(|e| e.function != 2);
+        assert_eq!(cpuid.entries(), [entry(1), entry(3)].as_ref());
+    }
+
+    #[test]
+    fn test_cpuid_entry2_from_raw() {
+        let entry = cpuid_entry2_from_raw(1, 0, 0x1111, 0x2222, 0x3333, 0x4444);
+        assert_eq!(entry.function, 1);
+        assert_eq!(entry.index, 0);
+        assert_eq!(entry.eax, 0x1111);
+        assert_eq!(entry.ebx, 0x2222);
+        assert_eq!(entry.ecx, 0x3333);
+        assert_eq!(entry.edx, 0x4444);
+    }
+}
@@ -0,0 +1,85 @@
+use std::fs::File;
+use std::io;
+use std::os::raw::c_char;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+use libc::{open, O_CLOEXEC, O_RDWR};
+
+use ioctls::vm::VmFd;
+use ioctls::Result;
+use kvm_ioctls::{KVM_CHECK_EXTENSION, KVM_CREATE_VM, KVM_GET_API_VERSION, KVM_GET_VCPU_MMAP_SIZE};
+use vmm_sys_util::ioctl::{ioctl, ioctl_with_val};
+
+/// Wrapper over KVM system ioctls.
+pub struct Kvm {
+    kvm: File,
+}
+
+impl Kvm {
+    /// Opens `/dev/kvm` and returns a `Kvm` object on success.
+    pub fn new() -> Result<Self> {
+        // Safe because we give a constant nul-terminated string and verify the result.
+        let ret = unsafe { open("/dev/kvm\0".as_ptr() as *const c_char, O_RDWR | O_CLOEXEC) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Safe because we verified that the fd is valid.
+        Ok(unsafe { Self::new_with_fd_number(ret) })
+    }
+
+    /// Creates a new `Kvm` object assuming `fd` represents an existing open file descriptor
+    /// associated with `/dev/kvm`.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe as the primitives currently returned have the contract that
+    /// they are the sole owner of the file descriptor they are wrapping.
+    pub unsafe fn new_with_fd_number(fd: RawFd) -> Self {
+        Kvm {
+            kvm: File::from_raw_fd(fd),
+        }
+    }
+
+    /// Returns the KVM API version.
+    pub fn get_api_version(&self) -> i32 {
+        // Safe because we know `self.kvm` is a valid KVM fd.
+        unsafe { ioctl(self, KVM_GET_API_VERSION()) }
+    }
+
+    /// Returns the size of the shared memory region used by the `kvm_run` struct.
+    pub fn get_vcpu_mmap_size(&self) -> Result<usize> {
+        // Safe because we know `self.kvm` is a valid KVM fd.
+        let res = unsafe { ioctl(self, KVM_GET_VCPU_MMAP_SIZE()) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(res as usize)
+    }
+
+    /// Creates a VM fd using the KVM fd.
+    pub fn create_vm(&self) -> Result<VmFd> {
+        // Safe because we know `self.kvm` is a valid KVM fd.
+        let ret = unsafe { ioctl(self, KVM_CREATE_VM()) };
+        if ret >= 0 {
+            // Safe because we verified the value of `ret` and we are the sole owners of the fd.
+            let vm_file = unsafe { File::from_raw_fd(ret) };
+            let run_size = self.get_vcpu_mmap_size()?;
+            Ok(VmFd::new(vm_file, run_size))
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    /// Checks if a particular `KVM_CAP_*` extension is available.
+    pub fn check_extension_int(&self, cap: u32) -> i32 {
+        // Safe because we know `self.kvm` is a valid KVM fd.
+        unsafe { ioctl_with_val(self, KVM_CHECK_EXTENSION(), cap as u64) }
+    }
+}
+
+impl AsRawFd for Kvm {
+    fn as_raw_fd(&self) -> RawFd {
+        self.kvm.as_raw_fd()
+    }
+}
@@ -0,0 +1,217 @@
+use std::fs::File;
+use std::io;
+use std::os::raw::c_int;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr::{null, null_mut};
+
+use kvm_bindings::{kvm_signal_mask, KVM_EXIT_FAIL_ENTRY, KVM_EXIT_HLT, KVM_EXIT_INTERNAL_ERROR,
+                   KVM_EXIT_IO, KVM_EXIT_IO_IN, KVM_EXIT_IO_OUT, KVM_EXIT_IRQ_WINDOW_OPEN,
+                   KVM_EXIT_MMIO, KVM_EXIT_SHUTDOWN};
+
+use ioctls::{vec_with_array_field, KvmRunWrapper, Result};
+use kvm_ioctls::{KVM_RUN, KVM_SET_CPUID2, KVM_SET_SIGNAL_MASK};
+use vmm_sys_util::ioctl::{ioctl, ioctl_with_ptr, ioctl_with_ref};
+
+/// Reasons for a VCPU exit, decoded from the anonymous union in `kvm_run`.
+///
+/// This allows the user to handle a VM exit without having to deal directly with the
+/// unstable, architecture-specific `kvm_run` structure themselves.
+#[derive(Debug)]
+pub enum VcpuExit<'a> {
+    /// An out-bound I/O port access, decoded from `kvm_run.io`. The `data` slice is the
+    /// guest-provided data, located in the shared memory region just past the `kvm_run`
+    /// struct.
+    IoOut { port: u16, data: &'a [u8] },
+    /// An in-bound I/O port access, decoded from `kvm_run.io`. `data` is the region of
+    /// the shared memory that the vmm should fill in with the value to return to the
+    /// guest.
+    IoIn { port: u16, data: &'a mut [u8] },
+    /// A read from an MMIO address, decoded from `kvm_run.mmio`. The vmm should fill in
+    /// `data` with the value read from `addr` before the next call to `run()`.
+    MmioRead { addr: u64, data: &'a mut [u8] },
+    /// A write to an MMIO address, decoded from `kvm_run.mmio`.
+    MmioWrite { addr: u64, data: &'a [u8] },
+    /// Corresponds to `KVM_EXIT_HLT`.
+    Hlt,
+    /// Corresponds to `KVM_EXIT_IRQ_WINDOW_OPEN`.
+    IrqWindowOpen,
+    /// Corresponds to `KVM_EXIT_SHUTDOWN`.
+    Shutdown,
+    /// Corresponds to `KVM_EXIT_FAIL_ENTRY`.
+    FailEntry,
+    /// Corresponds to `KVM_EXIT_INTERNAL_ERROR`.
+    InternalError,
+    /// The ioctl was interrupted by a signal delivered through the mask installed via
+    /// `set_signal_mask()`, i.e. the underlying `KVM_RUN` ioctl returned `EINTR`.
+    Intr,
+    /// An exit reason that does not have a dedicated variant yet. Carries the raw
+    /// `exit_reason` value so callers can still act on it.
+    Unknown(u32),
+}
+
+/// Size, in bytes, of the kernel's `sigset_t` as validated by `KVM_SET_SIGNAL_MASK` — a plain
+/// 64-bit bitmask (`_NSIG` / 8 on Linux, regardless of word size), far smaller than glibc's much
+/// larger opaque `libc::sigset_t`.
+const KVM_SIGNAL_MASK_LEN: usize = 8;
+
+/// Wrapper over a KVM VCPU fd.
+pub struct VcpuFd {
+    vcpu: File,
+    kvm_run: KvmRunWrapper,
+}
+
+impl VcpuFd {
+    /// Creates a new `VcpuFd` wrapping the given fd and its mmap'd `kvm_run` region.
+    ///
+    /// This should only be called by `VmFd::create_vcpu()`.
+    pub fn new(vcpu: File, kvm_run: KvmRunWrapper) -> Self {
+        VcpuFd { vcpu, kvm_run }
+    }
+
+    /// Sets the CPUID feature set for this VCPU via `KVM_SET_CPUID2`.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn set_cpuid2(&self, cpuid: &super::CpuId) -> Result<()> {
+        let ret = unsafe {
+            // Safe because we know that our file is a VCPU fd, we know the kernel will only
+            // read the correct amount of memory from our pointer, and we verify the return
+            // result.
+            ioctl_with_ptr(self, KVM_SET_CPUID2(), cpuid.as_ptr())
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Installs `signum` as the signal KVM will atomically unblock while the VCPU is inside
+    /// `KVM_RUN`, via `KVM_SET_SIGNAL_MASK`.
+    ///
+    /// `signum` is first blocked on the calling thread (so it can't be delivered outside of
+    /// `run()`), and the kernel is given a mask equal to the thread's blocked-signal set minus
+    /// `signum`, which it swaps in for the duration of the guest entry. A monitor thread can
+    /// then `pthread_kill(vcpu_thread, signum)` to force the VCPU out of `KVM_RUN`; `run()`
+    /// reports that as `VcpuExit::Intr` rather than an error.
+    ///
+    /// # Arguments
+    ///
+    /// * `signum` - The real-time signal number (e.g. `libc::SIGRTMIN()`) used to kick the VCPU.
+    ///
+    pub fn set_signal_mask(&self, signum: c_int) -> Result<()> {
+        // Safe because `current_mask` is a valid, zeroed `sigset_t` and we check the return
+        // values of the `pthread_sigmask` calls below.
+        let mut current_mask: libc::sigset_t = unsafe { std::mem::zeroed() };
+        // Safe because we pass a valid pointer to receive the thread's current mask.
+        let ret = unsafe { libc::pthread_sigmask(0, null(), &mut current_mask) };
+        if ret != 0 {
+            return Err(io::Error::from_raw_os_error(ret));
+        }
+        // Safe because `current_mask` is a valid `sigset_t`.
+        unsafe { libc::sigaddset(&mut current_mask, signum) };
+        // Safe because we pass a valid pointer to the mask that should now be blocked.
+        if unsafe { libc::pthread_sigmask(libc::SIG_SETMASK, &current_mask, null_mut()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut run_mask = current_mask;
+        // Safe because `run_mask` is a valid `sigset_t`; this unblocks `signum` only in the
+        // mask that KVM installs for the duration of `KVM_RUN`.
+        unsafe { libc::sigdelset(&mut run_mask, signum) };
+
+        // The kernel's sigset_t (what KVM_SET_SIGNAL_MASK validates `len` against) is only
+        // `KVM_SIGNAL_MASK_LEN` bytes; only that many leading bytes of `run_mask` carry the bit
+        // layout the kernel expects, so that's all we copy out of it.
+        let sigset_len = KVM_SIGNAL_MASK_LEN;
+        let mut kvm_mask = vec_with_array_field::<kvm_signal_mask, u8>(sigset_len);
+        kvm_mask[0].len = sigset_len as u32;
+        // Safe because `kvm_mask` was allocated with room for `sigset_len` trailing bytes and
+        // `run_mask` is at least `sigset_len` bytes of valid, initialized memory.
+        unsafe {
+            let mask_slice = kvm_mask[0].sigset.as_mut_slice(sigset_len);
+            let mask_bytes =
+                std::slice::from_raw_parts(&run_mask as *const libc::sigset_t as *const u8, sigset_len);
+            mask_slice.copy_from_slice(mask_bytes);
+        }
+
+        // Safe because we know that our file is a VCPU fd, the `kvm_signal_mask` we built has a
+        // correctly sized trailing `sigset`, and we verify the return result.
+        let ret = unsafe { ioctl_with_ref(self, KVM_SET_SIGNAL_MASK(), &kvm_mask[0]) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Runs the VCPU until the next exit and returns a safe, decoded `VcpuExit`.
+    ///
+    /// This replaces hand-decoding the `kvm_run.exit_reason` / union pair: the returned
+    /// `VcpuExit` borrows from the mmap'd `kvm_run` region so the caller never has to touch
+    /// the raw `repr(C)` union itself. If a signal mask was installed via `set_signal_mask()`
+    /// and the ioctl is interrupted by that signal, this returns `VcpuExit::Intr` instead of
+    /// propagating `EINTR` as an error.
+    pub fn run(&self) -> Result<VcpuExit> {
+        // Safe because we know that our file is a VCPU fd and we verify the return result.
+        let ret = unsafe { ioctl(self, KVM_RUN()) };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::EINTR) {
+                return Ok(VcpuExit::Intr);
+            }
+            return Err(e);
+        }
+
+        let run = self.kvm_run.as_mut_ref();
+        match run.exit_reason {
+            KVM_EXIT_IO => {
+                // Safe because the kernel guarantees that `io` is the active member of the
+                // union when `exit_reason` is `KVM_EXIT_IO`.
+                let io = unsafe { run.__bindgen_anon_1.io };
+                let port = io.port;
+                let data_size = io.count as usize * io.size as usize;
+                // Safe because the kernel told us, via `KVM_GET_VCPU_MMAP_SIZE`, that the
+                // mmap'd region is large enough to hold `kvm_run` plus the I/O data that
+                // follows it at `data_offset`.
+                let data_ptr = unsafe {
+                    (run as *mut kvm_bindings::kvm_run as *mut u8).add(io.data_offset as usize)
+                };
+                if io.direction as u32 == KVM_EXIT_IO_OUT {
+                    let data = unsafe { std::slice::from_raw_parts(data_ptr, data_size) };
+                    Ok(VcpuExit::IoOut { port, data })
+                } else {
+                    debug_assert_eq!(io.direction as u32, KVM_EXIT_IO_IN);
+                    let data = unsafe { std::slice::from_raw_parts_mut(data_ptr, data_size) };
+                    Ok(VcpuExit::IoIn { port, data })
+                }
+            }
+            KVM_EXIT_MMIO => {
+                // Safe because the kernel guarantees that `mmio` is the active member of the
+                // union when `exit_reason` is `KVM_EXIT_MMIO`.
+                let mmio = unsafe { &mut run.__bindgen_anon_1.mmio };
+                let len = mmio.len as usize;
+                let addr = mmio.phys_addr;
+                if mmio.is_write != 0 {
+                    Ok(VcpuExit::MmioWrite {
+                        addr,
+                        data: &mmio.data[..len],
+                    })
+                } else {
+                    Ok(VcpuExit::MmioRead {
+                        addr,
+                        data: &mut mmio.data[..len],
+                    })
+                }
+            }
+            KVM_EXIT_HLT => Ok(VcpuExit::Hlt),
+            KVM_EXIT_IRQ_WINDOW_OPEN => Ok(VcpuExit::IrqWindowOpen),
+            KVM_EXIT_SHUTDOWN => Ok(VcpuExit::Shutdown),
+            KVM_EXIT_FAIL_ENTRY => Ok(VcpuExit::FailEntry),
+            KVM_EXIT_INTERNAL_ERROR => Ok(VcpuExit::InternalError),
+            r => Ok(VcpuExit::Unknown(r)),
+        }
+    }
+}
+
+impl AsRawFd for VcpuFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.vcpu.as_raw_fd()
+    }
+}
@@ -0,0 +1,492 @@
+use std::fs::File;
+use std::io;
+use std::os::raw::c_void;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+use kvm_bindings::{kvm_clock_data, kvm_dirty_log, kvm_ioeventfd, kvm_irq_routing,
+                   kvm_irq_routing_entry, kvm_irqfd, kvm_userspace_memory_region,
+                   KVM_IRQFD_FLAG_DEASSIGN, KVM_IRQCHIP_IOAPIC, KVM_IRQCHIP_PIC_MASTER,
+                   KVM_IRQCHIP_PIC_SLAVE, KVM_IRQ_ROUTING_IRQCHIP, KVM_IRQ_ROUTING_MSI};
+
+use ioctls::vcpu::VcpuFd;
+use ioctls::{vec_with_array_field, KvmRunWrapper, Result};
+use kvm_ioctls::{KVM_CREATE_VCPU, KVM_GET_CLOCK, KVM_GET_DIRTY_LOG, KVM_IOEVENTFD, KVM_IRQFD,
+                 KVM_SET_CLOCK, KVM_SET_GSI_ROUTING, KVM_SET_USER_MEMORY_REGION};
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::ioctl::{ioctl_with_mut_ref, ioctl_with_ref, ioctl_with_val};
+
+/// Size, in bytes, of a guest page. Used to size the dirty bitmap returned by
+/// `get_dirty_log()`.
+const PAGE_SIZE: u64 = 4096;
+
+/// Number of `u64` words needed to hold one dirty bit per page of a `memory_size`-byte region.
+fn dirty_bitmap_len(memory_size: usize) -> usize {
+    let page_size = PAGE_SIZE as usize;
+    (memory_size + page_size * 64 - 1) / (page_size * 64)
+}
+
+/// `kvm_bindings` only generates the `KVM_IOEVENTFD_FLAG_*` bit indices, not the shifted
+/// flag masks `KVM_IOEVENTFD` actually takes, so those masks are defined locally here.
+const KVM_IOEVENTFD_FLAG_PIO: u32 = 1 << kvm_bindings::kvm_ioeventfd_flag_nr_pio;
+const KVM_IOEVENTFD_FLAG_DATAMATCH: u32 = 1 << kvm_bindings::kvm_ioeventfd_flag_nr_datamatch;
+const KVM_IOEVENTFD_FLAG_DEASSIGN: u32 = 1 << kvm_bindings::kvm_ioeventfd_flag_nr_deassign;
+
+/// Address of an ioeventfd trigger, to be matched against either guest PIO or MMIO accesses.
+#[derive(Debug, Clone, Copy)]
+pub enum IoEventAddress {
+    /// Trigger on accesses to the given port I/O address.
+    Pio(u64),
+    /// Trigger on accesses to the given MMIO address.
+    Mmio(u64),
+}
+
+/// The data to match an ioeventfd against, used to optionally restrict a `register_ioevent()`
+/// to writes of a specific value.
+#[derive(Debug, Clone, Copy)]
+pub enum Datamatch {
+    /// Trigger regardless of the value written, whatever its length.
+    AnyLength,
+    /// Trigger only for an 8-bit write equal to this value.
+    U8(u8),
+    /// Trigger only for a 16-bit write equal to this value.
+    U16(u16),
+    /// Trigger only for a 32-bit write equal to this value.
+    U32(u32),
+    /// Trigger only for a 64-bit write equal to this value.
+    U64(u64),
+}
+
+impl Datamatch {
+    fn len(&self) -> u32 {
+        match *self {
+            Datamatch::AnyLength => 0,
+            Datamatch::U8(_) => 1,
+            Datamatch::U16(_) => 2,
+            Datamatch::U32(_) => 4,
+            Datamatch::U64(_) => 8,
+        }
+    }
+
+    fn value(&self) -> u64 {
+        match *self {
+            Datamatch::AnyLength => 0,
+            Datamatch::U8(v) => u64::from(v),
+            Datamatch::U16(v) => u64::from(v),
+            Datamatch::U32(v) => u64::from(v),
+            Datamatch::U64(v) => v,
+        }
+    }
+}
+
+/// Selects an in-kernel IRQ chip input as the target of an `IrqSource::Irqchip` route.
+#[derive(Debug, Clone, Copy)]
+pub enum IrqChip {
+    /// The master PIC (GSIs 0-7).
+    PicMaster,
+    /// The slave PIC (GSIs 8-15).
+    PicSlave,
+    /// The IOAPIC.
+    Ioapic,
+}
+
+impl IrqChip {
+    fn as_kvm_irqchip(self) -> u32 {
+        match self {
+            IrqChip::PicMaster => KVM_IRQCHIP_PIC_MASTER,
+            IrqChip::PicSlave => KVM_IRQCHIP_PIC_SLAVE,
+            IrqChip::Ioapic => KVM_IRQCHIP_IOAPIC,
+        }
+    }
+}
+
+/// Where a GSI in the IRQ routing table delivers its interrupt.
+#[derive(Debug, Clone, Copy)]
+pub enum IrqSource {
+    /// Route to the given input `pin` of an in-kernel `chip`.
+    Irqchip { chip: IrqChip, pin: u32 },
+    /// Route as an MSI, writing `data` to `address`.
+    Msi { address: u64, data: u32 },
+}
+
+/// A single entry of the in-kernel IRQ routing table set by `VmFd::set_gsi_routing()`.
+#[derive(Debug, Clone, Copy)]
+pub struct IrqRoute {
+    /// The global system interrupt number being routed.
+    pub gsi: u32,
+    /// Where the GSI delivers its interrupt.
+    pub source: IrqSource,
+}
+
+impl IrqRoute {
+    fn to_kvm_entry(&self) -> kvm_irq_routing_entry {
+        let mut entry = kvm_irq_routing_entry {
+            gsi: self.gsi,
+            ..Default::default()
+        };
+
+        match self.source {
+            IrqSource::Irqchip { chip, pin } => {
+                entry.type_ = KVM_IRQ_ROUTING_IRQCHIP;
+                entry.u.irqchip.irqchip = chip.as_kvm_irqchip();
+                entry.u.irqchip.pin = pin;
+            }
+            IrqSource::Msi { address, data } => {
+                entry.type_ = KVM_IRQ_ROUTING_MSI;
+                entry.u.msi.address_lo = address as u32;
+                entry.u.msi.address_hi = (address >> 32) as u32;
+                entry.u.msi.data = data;
+            }
+        }
+
+        entry
+    }
+}
+
+/// A snapshot of the guest's paravirtual clock, as saved/restored by `VmFd::get_clock()` and
+/// `VmFd::set_clock()` around a pause for snapshot or migration.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ClockState {
+    /// Guest clock value, in nanoseconds.
+    pub clock: u64,
+    /// `KVM_CLOCK_*` flags describing the clock, e.g. whether it was paused.
+    pub flags: u32,
+}
+
+/// Wrapper over KVM VM ioctls.
+pub struct VmFd {
+    vm: File,
+    run_size: usize,
+}
+
+impl VmFd {
+    /// Creates a new `VmFd` wrapping the given fd.
+    ///
+    /// This should only be called by `Kvm::create_vm()`. `run_size` is the size, in bytes, of
+    /// the `kvm_run` shared memory region, as reported by `KVM_GET_VCPU_MMAP_SIZE`.
+    pub fn new(vm: File, run_size: usize) -> Self {
+        VmFd { vm, run_size }
+    }
+
+    /// Registers a guest memory region with this VM, via `KVM_SET_USER_MEMORY_REGION`.
+    ///
+    /// # Arguments
+    ///
+    /// * `slot` - Slot number, unique per `VmFd`, identifying this region so it can later be
+    ///   updated or removed by re-registering the same slot.
+    /// * `guest_phys_addr` - Guest physical address where the region starts.
+    /// * `memory_size` - Size of the region in bytes.
+    /// * `userspace_addr` - Address, in this process, of the memory backing the region.
+    /// * `flags` - A combination of `KVM_MEM_LOG_DIRTY_PAGES` (track writes so they can be
+    ///   retrieved with `get_dirty_log()`) and `KVM_MEM_READONLY` (trap guest writes instead of
+    ///   applying them).
+    pub fn set_user_memory_region(
+        &self,
+        slot: u32,
+        guest_phys_addr: u64,
+        memory_size: u64,
+        userspace_addr: u64,
+        flags: u32,
+    ) -> Result<()> {
+        let region = kvm_userspace_memory_region {
+            slot,
+            guest_phys_addr,
+            memory_size,
+            userspace_addr,
+            flags,
+        };
+
+        // Safe because we know that our file is a VM fd, we know the kernel will only read
+        // the correct amount of memory from our pointer, and we verify the return result.
+        let ret = unsafe { ioctl_with_ref(self, KVM_SET_USER_MEMORY_REGION(), &region) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Returns the dirty-page bitmap for the memory region registered under `slot`, via
+    /// `KVM_GET_DIRTY_LOG`. Only meaningful for slots registered with `KVM_MEM_LOG_DIRTY_PAGES`.
+    ///
+    /// The bitmap has one bit per guest page, packed into `u64` words; a set bit means the
+    /// corresponding page was written since the last call to `get_dirty_log()` for this slot
+    /// (or since the slot was registered, for the first call). The kernel clears the bits it
+    /// returns as part of this ioctl, so the log is automatically reset on every call; there is
+    /// no separate reset step, and re-registering the slot is not part of the cycle — doing so
+    /// would risk losing writes made during the window the slot is unregistered.
+    ///
+    /// # Arguments
+    ///
+    /// * `slot` - Slot number passed to `set_user_memory_region()` for this region.
+    /// * `memory_size` - Size, in bytes, of the region registered under `slot`.
+    pub fn get_dirty_log(&self, slot: u32, memory_size: usize) -> Result<Vec<u64>> {
+        let mut bitmap = vec![0u64; dirty_bitmap_len(memory_size)];
+
+        let mut dirty_log = kvm_dirty_log::default();
+        dirty_log.slot = slot;
+        // Safe because `dirty_bitmap` is the active member of the union for this call, and
+        // `bitmap` stays alive and correctly sized for the duration of the ioctl.
+        unsafe {
+            dirty_log.__bindgen_anon_1.dirty_bitmap = bitmap.as_mut_ptr() as *mut c_void;
+        }
+
+        // Safe because we know that our file is a VM fd, `dirty_log` points at a `bitmap` large
+        // enough for `memory_size`, and we verify the return result.
+        let ret = unsafe { ioctl_with_mut_ref(self, KVM_GET_DIRTY_LOG(), &mut dirty_log) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(bitmap)
+    }
+
+    /// Registers `fd` to be signalled, via `KVM_IOEVENTFD`, whenever the guest writes to `addr`,
+    /// optionally restricted to a specific `datamatch` value.
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - Eventfd signalled on a matching access.
+    /// * `addr` - PIO or MMIO address to watch.
+    /// * `datamatch` - Restricts triggering to writes carrying this value; `Datamatch::AnyLength`
+    ///   triggers on any write to `addr` regardless of length or value.
+    pub fn register_ioevent(
+        &self,
+        fd: &EventFd,
+        addr: &IoEventAddress,
+        datamatch: Datamatch,
+    ) -> Result<()> {
+        let mut flags = 0;
+        if let IoEventAddress::Pio(_) = *addr {
+            flags |= KVM_IOEVENTFD_FLAG_PIO;
+        }
+        if let Datamatch::AnyLength = datamatch {
+        } else {
+            flags |= KVM_IOEVENTFD_FLAG_DATAMATCH;
+        }
+
+        self.ioeventfd(fd, addr, datamatch, flags)
+    }
+
+    /// Unregisters a previously-registered ioeventfd for `addr`/`datamatch`, via
+    /// `KVM_IOEVENTFD` with `KVM_IOEVENTFD_FLAG_DEASSIGN`.
+    pub fn unregister_ioevent(
+        &self,
+        fd: &EventFd,
+        addr: &IoEventAddress,
+        datamatch: Datamatch,
+    ) -> Result<()> {
+        let mut flags = KVM_IOEVENTFD_FLAG_DEASSIGN;
+        if let IoEventAddress::Pio(_) = *addr {
+            flags |= KVM_IOEVENTFD_FLAG_PIO;
+        }
+        if let Datamatch::AnyLength = datamatch {
+        } else {
+            flags |= KVM_IOEVENTFD_FLAG_DATAMATCH;
+        }
+
+        self.ioeventfd(fd, addr, datamatch, flags)
+    }
+
+    fn ioeventfd(
+        &self,
+        fd: &EventFd,
+        addr: &IoEventAddress,
+        datamatch: Datamatch,
+        flags: u32,
+    ) -> Result<()> {
+        let addr = match *addr {
+            IoEventAddress::Pio(p) => p,
+            IoEventAddress::Mmio(m) => m,
+        };
+
+        let ioeventfd = kvm_ioeventfd {
+            addr,
+            len: datamatch.len(),
+            fd: fd.as_raw_fd(),
+            flags,
+            datamatch: datamatch.value(),
+            ..Default::default()
+        };
+
+        // Safe because we know that our file is a VM fd, we know the kernel will only read the
+        // correct amount of memory from our pointer, and we verify the return result.
+        let ret = unsafe { ioctl_with_ref(self, KVM_IOEVENTFD(), &ioeventfd) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Registers `fd` to be used to inject GSI `gsi`, via `KVM_IRQFD`: whenever `fd` is
+    /// signalled, KVM raises the corresponding in-kernel IRQ chip input.
+    pub fn register_irqfd(&self, fd: &EventFd, gsi: u32) -> Result<()> {
+        self.irqfd(fd, gsi, 0)
+    }
+
+    /// Unregisters a previously-registered irqfd for `gsi`, via `KVM_IRQFD` with
+    /// `KVM_IRQFD_FLAG_DEASSIGN`.
+    pub fn unregister_irqfd(&self, fd: &EventFd, gsi: u32) -> Result<()> {
+        self.irqfd(fd, gsi, KVM_IRQFD_FLAG_DEASSIGN)
+    }
+
+    fn irqfd(&self, fd: &EventFd, gsi: u32, flags: u32) -> Result<()> {
+        let irqfd = kvm_irqfd {
+            fd: fd.as_raw_fd() as u32,
+            gsi,
+            flags,
+            ..Default::default()
+        };
+
+        // Safe because we know that our file is a VM fd, we know the kernel will only read the
+        // correct amount of memory from our pointer, and we verify the return result.
+        let ret = unsafe { ioctl_with_ref(self, KVM_IRQFD(), &irqfd) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Sets the complete in-kernel IRQ routing table, via `KVM_SET_GSI_ROUTING`. Each `route`
+    /// wires a GSI to either a PIC/IOAPIC input pin or an MSI message, replacing any routing
+    /// previously set for that GSI.
+    pub fn set_gsi_routing(&self, routes: &[IrqRoute]) -> Result<()> {
+        let mut irq_routing =
+            vec_with_array_field::<kvm_irq_routing, kvm_irq_routing_entry>(routes.len());
+        irq_routing[0].nr = routes.len() as u32;
+
+        // Safe because we allocated `irq_routing` with exactly `routes.len()` trailing entries.
+        let entries = unsafe { irq_routing[0].entries.as_mut_slice(routes.len()) };
+        for (entry, route) in entries.iter_mut().zip(routes.iter()) {
+            *entry = route.to_kvm_entry();
+        }
+
+        // Safe because we know that our file is a VM fd, `irq_routing` was allocated with room
+        // for exactly `routes.len()` entries, and we verify the return result.
+        let ret = unsafe { ioctl_with_ref(self, KVM_SET_GSI_ROUTING(), &irq_routing[0]) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Captures the guest's paravirtual clock, via `KVM_GET_CLOCK`, so it can be reinjected with
+    /// `set_clock()` after a snapshot restore or migration without the guest observing a jump.
+    pub fn get_clock(&self) -> Result<ClockState> {
+        let mut clock_data = kvm_clock_data::default();
+        // Safe because we know that our file is a VM fd, `clock_data` is large enough to hold
+        // the ioctl's output, and we verify the return result.
+        let ret = unsafe { ioctl_with_mut_ref(self, KVM_GET_CLOCK(), &mut clock_data) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ClockState {
+            clock: clock_data.clock,
+            flags: clock_data.flags,
+        })
+    }
+
+    /// Restores a guest paravirtual clock previously captured with `get_clock()`, via
+    /// `KVM_SET_CLOCK`.
+    pub fn set_clock(&self, clock_state: &ClockState) -> Result<()> {
+        let clock_data = kvm_clock_data {
+            clock: clock_state.clock,
+            flags: clock_state.flags,
+            ..Default::default()
+        };
+
+        // Safe because we know that our file is a VM fd, we know the kernel will only read the
+        // correct amount of memory from our pointer, and we verify the return result.
+        let ret = unsafe { ioctl_with_ref(self, KVM_SET_CLOCK(), &clock_data) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Creates a new VCPU with the given `id` and returns its file descriptor wrapper.
+    pub fn create_vcpu(&self, id: u8) -> Result<VcpuFd> {
+        // Safe because we know that our file is a VM fd and we verify the return result.
+        let vcpu_fd = unsafe { ioctl_with_val(self, KVM_CREATE_VCPU(), id as u64) };
+        if vcpu_fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Safe because we verified the value of `vcpu_fd` and we are the sole owners of it.
+        let vcpu_file = unsafe { File::from_raw_fd(vcpu_fd) };
+        let kvm_run = KvmRunWrapper::mmap_from_fd(&vcpu_file, self.run_size)?;
+
+        Ok(VcpuFd::new(vcpu_file, kvm_run))
+    }
+}
+
+impl AsRawFd for VmFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.vm.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dirty_bitmap_len() {
+        assert_eq!(dirty_bitmap_len(0), 0);
+        assert_eq!(dirty_bitmap_len(1), 1);
+        assert_eq!(dirty_bitmap_len(64 * 4096), 1);
+        assert_eq!(dirty_bitmap_len(64 * 4096 + 1), 2);
+        assert_eq!(dirty_bitmap_len(128 * 4096), 2);
+    }
+
+    #[test]
+    fn test_datamatch_len_and_value() {
+        assert_eq!(Datamatch::AnyLength.len(), 0);
+        assert_eq!(Datamatch::AnyLength.value(), 0);
+        assert_eq!(Datamatch::U8(0xab).len(), 1);
+        assert_eq!(Datamatch::U8(0xab).value(), 0xab);
+        assert_eq!(Datamatch::U16(0x1234).len(), 2);
+        assert_eq!(Datamatch::U16(0x1234).value(), 0x1234);
+        assert_eq!(Datamatch::U32(0x1234_5678).len(), 4);
+        assert_eq!(Datamatch::U32(0x1234_5678).value(), 0x1234_5678);
+        assert_eq!(Datamatch::U64(0x1234_5678_9abc_def0).len(), 8);
+        assert_eq!(
+            Datamatch::U64(0x1234_5678_9abc_def0).value(),
+            0x1234_5678_9abc_def0
+        );
+    }
+
+    #[test]
+    fn test_irq_route_to_kvm_entry_irqchip() {
+        let route = IrqRoute {
+            gsi: 5,
+            source: IrqSource::Irqchip {
+                chip: IrqChip::Ioapic,
+                pin: 10,
+            },
+        };
+        let entry = route.to_kvm_entry();
+        assert_eq!(entry.gsi, 5);
+        assert_eq!(entry.type_, KVM_IRQ_ROUTING_IRQCHIP);
+        unsafe {
+            assert_eq!(entry.u.irqchip.irqchip, KVM_IRQCHIP_IOAPIC);
+            assert_eq!(entry.u.irqchip.pin, 10);
+        }
+    }
+
+    #[test]
+    fn test_irq_route_to_kvm_entry_msi() {
+        let route = IrqRoute {
+            gsi: 7,
+            source: IrqSource::Msi {
+                address: 0xfee0_0000_1234,
+                data: 0xabcd,
+            },
+        };
+        let entry = route.to_kvm_entry();
+        assert_eq!(entry.gsi, 7);
+        assert_eq!(entry.type_, KVM_IRQ_ROUTING_MSI);
+        unsafe {
+            assert_eq!(entry.u.msi.address_lo, 0x0000_1234);
+            assert_eq!(entry.u.msi.address_hi, 0xfee0);
+            assert_eq!(entry.u.msi.data, 0xabcd);
+        }
+    }
+}
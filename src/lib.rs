@@ -0,0 +1,18 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! A safe wrapper around the kernel's KVM interface.
+
+extern crate kvm_bindings;
+extern crate libc;
+extern crate vmm_sys_util;
+
+mod ioctls;
+mod kvm_ioctls;
+
+pub use ioctls::system::Kvm;
+pub use ioctls::vcpu::{VcpuExit, VcpuFd};
+pub use ioctls::vm::{ClockState, Datamatch, IoEventAddress, IrqChip, IrqRoute, IrqSource, VmFd};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use ioctls::cpuid_entry2_from_raw;
+pub use ioctls::{CpuId, KvmRunWrapper, Result};